@@ -1,188 +1,307 @@
-use crate::SharedString;
-use itertools::Itertools;
 use schemars::{
     schema::{InstanceType, Schema, SchemaObject, SingleOrVec},
     JsonSchema,
 };
+use smallvec::SmallVec;
 
-macro_rules! create_definitions {
-    ($($(#[$meta:meta])* ($name:ident, $idx:expr)),* $(,)?) => {
-
-        /// The OpenType features that can be configured for a given font.
-        #[derive(Default, Clone, Eq, PartialEq, Hash)]
-        pub struct FontFeatures {
-            enabled: u64,
-            disabled: u64,
-            other_enabled: SharedString,
-            other_disabled: SharedString,
+/// A feature value, as specified in settings: either a simple on/off toggle,
+/// or a numeric selector (e.g. to pick a stylistic-set/character-variant or
+/// an alternate glyph).
+enum FeatureValue {
+    Bool(bool),
+    Int(u32),
+}
+
+impl FeatureValue {
+    fn into_u32(self) -> u32 {
+        match self {
+            FeatureValue::Bool(value) => value as u32,
+            FeatureValue::Int(value) => value,
         }
+    }
+}
 
-        impl FontFeatures {
-            $(
-                /// Get the current value of the corresponding OpenType feature
-                pub fn $name(&self) -> Option<bool> {
-                    if (self.enabled & (1 << $idx)) != 0 {
-                        Some(true)
-                    } else if (self.disabled & (1 << $idx)) != 0 {
-                        Some(false)
-                    } else {
-                        None
-                    }
-                }
-            )*
+impl<'de> serde::Deserialize<'de> for FeatureValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct FeatureValueVisitor;
 
-            /// Get the tag name list of the font OpenType features
-            /// only enabled or disabled features are returned
-            pub fn tag_value_list(&self) -> Vec<(String, bool)> {
-                let mut result = Vec::new();
-                $(
-                    {
-                        let value = if (self.enabled & (1 << $idx)) != 0 {
-                            Some(true)
-                        } else if (self.disabled & (1 << $idx)) != 0 {
-                            Some(false)
-                        } else {
-                            None
-                        };
-                        if let Some(enable) = value {
-                            let tag_name = stringify!($name).to_owned();
-                            result.push((tag_name, enable));
-                        }
-                    }
-                )*
-                {
-                    for name in self.other_enabled.as_ref().chars().chunks(4).into_iter() {
-                        result.push((name.collect::<String>(), true));
-                    }
-                    for name in self.other_disabled.as_ref().chars().chunks(4).into_iter() {
-                        result.push((name.collect::<String>(), false));
-                    }
-                }
-                result
+        impl serde::de::Visitor<'_> for FeatureValueVisitor {
+            type Value = FeatureValue;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a boolean or a non-negative integer")
+            }
+
+            fn visit_bool<E>(self, value: bool) -> Result<Self::Value, E> {
+                Ok(FeatureValue::Bool(value))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                u32::try_from(value)
+                    .map(FeatureValue::Int)
+                    .map_err(|_| E::custom(format!("feature value out of range: {}", value)))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                u32::try_from(value)
+                    .map(FeatureValue::Int)
+                    .map_err(|_| E::custom(format!("feature value must be non-negative: {}", value)))
             }
         }
 
-        impl std::fmt::Debug for FontFeatures {
-            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                let mut debug = f.debug_struct("FontFeatures");
-                $(
-                    if let Some(value) = self.$name() {
-                        debug.field(stringify!($name), &value);
-                    };
-                )*
-                #[cfg(target_os = "windows")]
-                {
-                    for name in self.other_enabled.as_ref().chars().chunks(4).into_iter() {
-                        debug.field(name.collect::<String>().as_str(), &true);
-                    }
-                    for name in self.other_disabled.as_ref().chars().chunks(4).into_iter() {
-                        debug.field(name.collect::<String>().as_str(), &false);
-                    }
+        deserializer.deserialize_any(FeatureValueVisitor)
+    }
+}
+
+/// A 4-byte OpenType feature tag, e.g. `calt` or `cv01`.
+#[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash)]
+pub struct FeatureTag([u8; 4]);
+
+impl FeatureTag {
+    /// Creates a feature tag from a 4-byte ASCII string known at compile time.
+    const fn new(tag: &str) -> Self {
+        let bytes = tag.as_bytes();
+        assert!(bytes.len() == 4, "feature tags must be exactly 4 bytes");
+        FeatureTag([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).unwrap_or("????")
+    }
+}
+
+impl std::fmt::Debug for FeatureTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a string could not be parsed as a [`FeatureTag`].
+///
+/// Follows the OpenType tag grammar: tags are exactly 4 ASCII characters,
+/// where only trailing characters may be spaces (used to pad tags shorter
+/// than 4 characters) and every other character must be alphanumeric.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidFeatureTag {
+    /// The tag was not exactly 4 characters long.
+    WrongLength(usize),
+    /// The tag contained a non-ASCII character.
+    NotAscii,
+    /// The tag contained a character that isn't alphanumeric, or a space
+    /// that wasn't part of a trailing run (e.g. a leading space).
+    InvalidChar(char),
+}
+
+impl std::fmt::Display for InvalidFeatureTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidFeatureTag::WrongLength(len) => {
+                write!(f, "feature tags must be exactly 4 characters, got {}", len)
+            }
+            InvalidFeatureTag::NotAscii => write!(f, "feature tags must be ASCII"),
+            InvalidFeatureTag::InvalidChar(c) => write!(
+                f,
+                "feature tags may only contain alphanumeric characters or trailing spaces, found {:?}",
+                c
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidFeatureTag {}
+
+impl TryFrom<&str> for FeatureTag {
+    type Error = InvalidFeatureTag;
+
+    fn try_from(tag: &str) -> Result<Self, Self::Error> {
+        let char_count = tag.chars().count();
+        if char_count != 4 {
+            return Err(InvalidFeatureTag::WrongLength(char_count));
+        }
+        if !tag.is_ascii() {
+            return Err(InvalidFeatureTag::NotAscii);
+        }
+
+        let bytes = tag.as_bytes();
+        if bytes[0] == b' ' {
+            return Err(InvalidFeatureTag::InvalidChar(' '));
+        }
+        let mut in_trailing_spaces = false;
+        for &byte in bytes {
+            let c = byte as char;
+            if in_trailing_spaces {
+                if c != ' ' {
+                    // the space we thought started a trailing run turned out
+                    // to be an interior space, which is what actually
+                    // violates the grammar.
+                    return Err(InvalidFeatureTag::InvalidChar(' '));
                 }
-                debug.finish()
+            } else if c == ' ' {
+                in_trailing_spaces = true;
+            } else if !c.is_ascii_alphanumeric() {
+                return Err(InvalidFeatureTag::InvalidChar(c));
             }
         }
 
-        impl<'de> serde::Deserialize<'de> for FontFeatures {
-            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-            where
-                D: serde::Deserializer<'de>,
-            {
-                use serde::de::{MapAccess, Visitor};
-                use std::fmt;
+        Ok(FeatureTag([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
 
-                struct FontFeaturesVisitor;
+impl std::str::FromStr for FeatureTag {
+    type Err = InvalidFeatureTag;
 
-                impl<'de> Visitor<'de> for FontFeaturesVisitor {
-                    type Value = FontFeatures;
+    fn from_str(tag: &str) -> Result<Self, Self::Err> {
+        Self::try_from(tag)
+    }
+}
 
-                    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                        formatter.write_str("a map of font features")
-                    }
+/// The OpenType features that can be configured for a given font.
+///
+/// Features are stored as a compact, canonically-sorted list of
+/// `(tag, value)` pairs rather than a fixed-width bitmask, so there's no
+/// ceiling on the number of known tags and a tag this crate doesn't have a
+/// typed accessor for lives in the same representation as one it does. This
+/// also means any tag — known or not — round-trips through
+/// `Deserialize`/`Serialize`/`Debug` unchanged, on every platform, instead of
+/// being dropped or only shown under a platform-specific `cfg`.
+#[derive(Default, Clone, Eq, PartialEq, Hash)]
+pub struct FontFeatures(SmallVec<[(FeatureTag, u32); 4]>);
+
+impl FontFeatures {
+    fn get(&self, tag: FeatureTag) -> Option<u32> {
+        self.0
+            .binary_search_by_key(&tag, |(tag, _)| *tag)
+            .ok()
+            .map(|index| self.0[index].1)
+    }
+
+    fn set(&mut self, tag: FeatureTag, value: u32) {
+        match self.0.binary_search_by_key(&tag, |(tag, _)| *tag) {
+            Ok(index) => self.0[index].1 = value,
+            Err(index) => self.0.insert(index, (tag, value)),
+        }
+    }
+
+    /// Get the tag name list of the font OpenType features, including any
+    /// tag this crate doesn't have a typed accessor for.
+    pub fn tag_value_list(&self) -> Vec<(String, u32)> {
+        self.0
+            .iter()
+            .map(|(tag, value)| (tag.as_str().to_owned(), *value))
+            .collect()
+    }
+
+    /// Returns a new `FontFeatures` that starts from `self` and applies every
+    /// tag explicitly set in `overrides`, leaving any tag the override
+    /// doesn't mention untouched. This is how font-feature settings compose
+    /// across layered scopes (e.g. default settings → user settings →
+    /// per-language buffer font), where a more-specific scope should only
+    /// override the tags it actually mentions rather than replacing the
+    /// whole set.
+    pub fn merged_with(&self, overrides: &FontFeatures) -> FontFeatures {
+        let mut result = self.clone();
+        for (tag, value) in overrides.0.iter() {
+            result.set(*tag, *value);
+        }
+        result
+    }
+}
+
+impl std::fmt::Debug for FontFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("FontFeatures");
+        for (tag, value) in self.0.iter() {
+            debug.field(tag.as_str(), value);
+        }
+        debug.finish()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FontFeatures {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::{MapAccess, Visitor};
+        use std::fmt;
+
+        struct FontFeaturesVisitor;
+
+        impl<'de> Visitor<'de> for FontFeaturesVisitor {
+            type Value = FontFeatures;
 
-                    fn visit_map<M>(self, mut access: M) -> Result<Self::Value, M::Error>
-                    where
-                        M: MapAccess<'de>,
-                    {
-                        let mut enabled: u64 = 0;
-                        let mut disabled: u64 = 0;
-                        let mut other_enabled = "".to_owned();
-                        let mut other_disabled = "".to_owned();
-
-                        while let Some((key, value)) = access.next_entry::<String, Option<bool>>()? {
-                            let idx = match key.as_str() {
-                                $(stringify!($name) => Some($idx),)*
-                                other_feature => {
-                                    if other_feature.len() != 4 || !other_feature.is_ascii() {
-                                        log::error!("Incorrect feature name: {}", other_feature);
-                                        continue;
-                                    }
-                                    None
-                                },
-                            };
-                            if let Some(idx) = idx {
-                                match value {
-                                    Some(true) => enabled |= 1 << idx,
-                                    Some(false) => disabled |= 1 << idx,
-                                    None => {}
-                                };
-                            } else {
-                                match value {
-                                    Some(true) => other_enabled.push_str(key.as_str()),
-                                    Some(false) => other_disabled.push_str(key.as_str()),
-                                    None => {}
-                                };
-                            }
-                        }
-                        let other_enabled = if other_enabled.is_empty() {
-                            "".into()
-                        } else {
-                            other_enabled.into()
-                        };
-                        let other_disabled = if other_disabled.is_empty() {
-                            "".into()
-                        } else {
-                            other_disabled.into()
-                        };
-                        Ok(FontFeatures { enabled, disabled, other_enabled, other_disabled })
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of font features")
+            }
+
+            fn visit_map<M>(self, mut access: M) -> Result<Self::Value, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                let mut features = FontFeatures::default();
+
+                while let Some((key, value)) = access.next_entry::<String, Option<FeatureValue>>()? {
+                    let tag = FeatureTag::try_from(key.as_str()).map_err(|error| {
+                        serde::de::Error::custom(format!(
+                            "invalid feature tag {:?}: {}",
+                            key, error
+                        ))
+                    })?;
+                    if let Some(value) = value {
+                        features.set(tag, value.into_u32());
                     }
                 }
 
-                let features = deserializer.deserialize_map(FontFeaturesVisitor)?;
                 Ok(features)
             }
         }
 
-        impl serde::Serialize for FontFeatures {
-            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-            where
-                S: serde::Serializer,
-            {
-                use serde::ser::SerializeMap;
+        deserializer.deserialize_map(FontFeaturesVisitor)
+    }
+}
 
-                let mut map = serializer.serialize_map(None)?;
+impl serde::Serialize for FontFeatures {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
 
-                $(
-                    {
-                        let feature = stringify!($name);
-                        if let Some(value) = self.$name() {
-                            map.serialize_entry(feature, &value)?;
-                        }
-                    }
-                )*
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (tag, value) in self.0.iter() {
+            match value {
+                0 => map.serialize_entry(tag.as_str(), &false)?,
+                1 => map.serialize_entry(tag.as_str(), &true)?,
+                value => map.serialize_entry(tag.as_str(), value)?,
+            }
+        }
+        map.end()
+    }
+}
 
-                #[cfg(target_os = "windows")]
-                {
-                    for name in self.other_enabled.as_ref().chars().chunks(4).into_iter() {
-                        map.serialize_entry(name.collect::<String>().as_str(), &true)?;
-                    }
-                    for name in self.other_disabled.as_ref().chars().chunks(4).into_iter() {
-                        map.serialize_entry(name.collect::<String>().as_str(), &false)?;
-                    }
+macro_rules! create_definitions {
+    ($($(#[$meta:meta])* $name:ident),* $(,)?) => {
+        impl FontFeatures {
+            $(
+                /// Get the current value of the corresponding OpenType feature.
+                ///
+                /// `Some(0)` means the feature is explicitly disabled, `Some(1)` means
+                /// it is enabled with its default value, and any other value is the
+                /// explicit numeric selector the feature was given (e.g. for `aalt`,
+                /// `salt`, or the `cvNN`/`ssNN` families).
+                pub fn $name(&self) -> Option<u32> {
+                    self.get(FeatureTag::new(stringify!($name)))
                 }
-
-                map.end()
-            }
+            )*
         }
 
         impl JsonSchema for FontFeatures {
@@ -194,7 +313,10 @@ macro_rules! create_definitions {
                 let mut schema = SchemaObject::default();
                 let properties = &mut schema.object().properties;
                 let feature_schema = Schema::Object(SchemaObject {
-                    instance_type: Some(SingleOrVec::Single(Box::new(InstanceType::Boolean))),
+                    instance_type: Some(SingleOrVec::Vec(vec![
+                        InstanceType::Boolean,
+                        InstanceType::Integer,
+                    ])),
                     ..Default::default()
                 });
 
@@ -209,38 +331,122 @@ macro_rules! create_definitions {
 }
 
 create_definitions!(
-    (calt, 0),
-    (case, 1),
-    (cpsp, 2),
-    (frac, 3),
-    (liga, 4),
-    (onum, 5),
-    (ordn, 6),
-    (pnum, 7),
-    (ss01, 8),
-    (ss02, 9),
-    (ss03, 10),
-    (ss04, 11),
-    (ss05, 12),
-    (ss06, 13),
-    (ss07, 14),
-    (ss08, 15),
-    (ss09, 16),
-    (ss10, 17),
-    (ss11, 18),
-    (ss12, 19),
-    (ss13, 20),
-    (ss14, 21),
-    (ss15, 22),
-    (ss16, 23),
-    (ss17, 24),
-    (ss18, 25),
-    (ss19, 26),
-    (ss20, 27),
-    (subs, 28),
-    (sups, 29),
-    (swsh, 30),
-    (titl, 31),
-    (tnum, 32),
-    (zero, 33),
+    calt, case, cpsp, frac, liga, onum, ordn, pnum, ss01, ss02, ss03, ss04, ss05, ss06, ss07,
+    ss08, ss09, ss10, ss11, ss12, ss13, ss14, ss15, ss16, ss17, ss18, ss19, ss20, subs, sups,
+    swsh, titl, tnum, zero,
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_valued_feature_round_trips() {
+        let features: FontFeatures = serde_json::from_value(serde_json::json!({
+            "ss01": 3,
+            "calt": true,
+            "liga": false,
+        }))
+        .unwrap();
+
+        assert_eq!(features.ss01(), Some(3));
+        assert_eq!(features.calt(), Some(1));
+        assert_eq!(features.liga(), Some(0));
+
+        let value = serde_json::to_value(&features).unwrap();
+        assert_eq!(value["ss01"], serde_json::json!(3));
+        assert_eq!(value["calt"], serde_json::json!(true));
+        assert_eq!(value["liga"], serde_json::json!(false));
+    }
+
+    #[test]
+    fn merged_with_is_order_independent_and_applies_explicit_overrides() {
+        use std::hash::{Hash, Hasher};
+
+        fn hash_of(features: &FontFeatures) -> u64 {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            features.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let a: FontFeatures = serde_json::from_value(serde_json::json!({
+            "calt": true,
+            "liga": true,
+        }))
+        .unwrap();
+        let b: FontFeatures = serde_json::from_value(serde_json::json!({
+            "liga": true,
+            "calt": true,
+        }))
+        .unwrap();
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+
+        let overrides: FontFeatures = serde_json::from_value(serde_json::json!({
+            "calt": false,
+        }))
+        .unwrap();
+
+        let merged_a = a.merged_with(&overrides);
+        let merged_b = b.merged_with(&overrides);
+
+        // the explicit override wins...
+        assert_eq!(merged_a.calt(), Some(0));
+        // ...but the tag the override doesn't mention survives from `self`.
+        assert_eq!(merged_a.liga(), Some(1));
+        // merging is deterministic regardless of the order tags were set in.
+        assert_eq!(merged_a, merged_b);
+        assert_eq!(hash_of(&merged_a), hash_of(&merged_b));
+    }
+
+    #[test]
+    fn unknown_tags_round_trip_on_every_platform() {
+        let features: FontFeatures = serde_json::from_value(serde_json::json!({
+            "zzzz": true,
+            "yyyy": false,
+        }))
+        .unwrap();
+
+        // no `#[cfg(target_os = "windows")]` gate: unknown tags show up in
+        // `Debug` and re-serialize regardless of platform.
+        let debug = format!("{:?}", features);
+        assert!(debug.contains("yyyy: 0"));
+        assert!(debug.contains("zzzz: 1"));
+
+        let value = serde_json::to_value(&features).unwrap();
+        assert_eq!(value["zzzz"], serde_json::json!(true));
+        assert_eq!(value["yyyy"], serde_json::json!(false));
+    }
+
+    #[test]
+    fn feature_tag_validates_opentype_grammar() {
+        // too short
+        assert_eq!(
+            FeatureTag::try_from("ss1"),
+            Err(InvalidFeatureTag::WrongLength(3))
+        );
+        // trailing spaces pad short tags, and are allowed
+        assert!(FeatureTag::try_from("cv1 ").is_ok());
+        // but a leading space is not
+        assert_eq!(
+            FeatureTag::try_from(" alt"),
+            Err(InvalidFeatureTag::InvalidChar(' '))
+        );
+        // nor is an interior space
+        assert_eq!(
+            FeatureTag::try_from("ca t"),
+            Err(InvalidFeatureTag::InvalidChar(' '))
+        );
+        // 4 characters, but not ASCII
+        assert_eq!(FeatureTag::try_from("café"), Err(InvalidFeatureTag::NotAscii));
+    }
+
+    #[test]
+    fn invalid_feature_tag_is_a_real_deserialize_error() {
+        let error = serde_json::from_value::<FontFeatures>(serde_json::json!({
+            "ss1": true,
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("ss1"));
+    }
+}